@@ -1,13 +1,34 @@
 use anyhow::{anyhow, Context, Result};
-use std::{env::args, str::from_utf8};
+use flate2::{write::GzEncoder, Compression};
+use std::collections::HashMap;
+use std::env::args;
+use std::future::Future;
+use std::io::Write;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::{
     fs::File,
     io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpStream},
     spawn,
+    time::{timeout_at, Instant},
 };
 
 const MAX_REQUEST_SIZE: usize = 1024 * 32; // 32 KB
+// default for `max_requests_per_connection`, overridable via
+// `--max-requests-per-connection`
+const DEFAULT_MAX_REQUESTS_PER_CONNECTION: u32 = 100;
+// identity response bodies larger than this are streamed with
+// `Transfer-Encoding: chunked` instead of a precomputed `Content-Length`
+const CHUNKED_RESPONSE_THRESHOLD: usize = 1024 * 16;
+const CHUNK_SIZE: usize = 1024 * 8;
+// headers larger than this get a 431 instead of growing the buffer forever
+const MAX_HTTP_MESSAGE_HEADER_SIZE: usize = 1024 * 8; // 8 KB
+// how long we'll wait for a client to send (more of) its request headers
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+// how long we'll wait for the rest of a request once headers have arrived
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
 
 // structures for HTTP requests and responses
 #[allow(dead_code)]
@@ -27,7 +48,24 @@ struct Header {
 struct Request {
     request_line: RequestLine,
     headers: Vec<Header>,
-    body: String,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn header(&self, key: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|header| header.key.eq_ignore_ascii_case(key))
+            .map(|header| header.value.as_str())
+    }
+
+    // whether the client asked to keep this connection open for another request
+    fn wants_keep_alive(&self) -> bool {
+        match self.header("Connection") {
+            Some(value) => !value.eq_ignore_ascii_case("close"),
+            None => self.request_line.version != "HTTP/1.0",
+        }
+    }
 }
 
 #[allow(dead_code)]
@@ -41,87 +79,619 @@ struct StatusLine {
 struct Response {
     status_line: StatusLine,
     headers: Vec<Header>,
-    body: String,
+    body: Vec<u8>,
+    // whether `body` should be framed as `Transfer-Encoding: chunked` rather
+    // than preceded by a `Content-Length` header
+    chunked: bool,
+}
+
+impl Response {
+    fn set_header(&mut self, key: &str, value: String) {
+        match self
+            .headers
+            .iter_mut()
+            .find(|header| header.key.eq_ignore_ascii_case(key))
+        {
+            Some(header) => header.value = value,
+            None => self.headers.push(Header {
+                key: key.to_owned(),
+                value,
+            }),
+        }
+    }
+}
+
+// path parameters captured from a route pattern, e.g. `:msg` in `/echo/:msg`
+type Params = HashMap<String, String>;
+
+// everything a handler needs: the request that matched its route, the path
+// parameters captured along the way, and the negotiated response encoding
+struct HandlerInput {
+    request: Request,
+    params: Params,
+    accepts_gzip: bool,
+}
+
+type HandlerFuture = Pin<Box<dyn Future<Output = Result<Response>> + Send>>;
+type BoxedHandler = Box<dyn Fn(HandlerInput) -> HandlerFuture + Send + Sync>;
+
+// a route pattern split into literal and named (`:param`) segments
+enum Segment {
+    Literal(String),
+    Param(String),
+}
+
+// splits a `/`-delimited path (or pattern) into segments. only the leading
+// slash is stripped, so a trailing slash still yields a trailing empty
+// segment instead of being silently dropped - e.g. `/echo/` has a 2nd
+// segment of `""`, matching `/echo/:msg` with `msg` bound to the empty string
+fn path_segments(path: &str) -> Vec<&str> {
+    path.strip_prefix('/').unwrap_or(path).split('/').collect()
+}
+
+fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    path_segments(pattern)
+        .into_iter()
+        .map(|segment| match segment.strip_prefix(':') {
+            Some(name) => Segment::Param(name.to_owned()),
+            None => Segment::Literal(segment.to_owned()),
+        })
+        .collect()
+}
+
+// matches `path` against `pattern` segment-for-segment, capturing `:param`
+// segments along the way; `None` if the segment counts or literals differ
+fn match_pattern(pattern: &[Segment], path: &str) -> Option<Params> {
+    let path_segments = path_segments(path);
+    if pattern.len() != path_segments.len() {
+        return None;
+    }
+
+    let mut params = Params::new();
+    for (segment, value) in pattern.iter().zip(path_segments) {
+        match segment {
+            Segment::Literal(literal) if literal == value => {}
+            Segment::Literal(_) => return None,
+            Segment::Param(name) => {
+                params.insert(name.clone(), value.to_owned());
+            }
+        }
+    }
+    Some(params)
+}
+
+struct Route {
+    method: String,
+    pattern: Vec<Segment>,
+    handler: BoxedHandler,
+}
+
+// a table mapping `(method, pattern)` to handlers, modeled so that adding an
+// endpoint is just another `add` call rather than a new `match` arm
+struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    fn new() -> Self {
+        Router { routes: Vec::new() }
+    }
+
+    fn add<F, Fut>(&mut self, method: &str, pattern: &str, handler: F)
+    where
+        F: Fn(HandlerInput) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Response>> + Send + 'static,
+    {
+        self.routes.push(Route {
+            method: method.to_owned(),
+            pattern: parse_pattern(pattern),
+            handler: Box::new(move |input| Box::pin(handler(input))),
+        });
+    }
+
+    // finds the first route whose method and pattern match the request's
+    // target, and runs its handler; falls back to a plain 404
+    async fn dispatch(&self, request: Request, accepts_gzip: bool) -> Result<Response> {
+        let method = request.request_line.method.clone();
+        let path = request
+            .request_line
+            .target
+            .split('?')
+            .next()
+            .unwrap_or("")
+            .to_owned();
+
+        for route in &self.routes {
+            if route.method != method {
+                continue;
+            }
+            if let Some(params) = match_pattern(&route.pattern, &path) {
+                return (route.handler)(HandlerInput {
+                    request,
+                    params,
+                    accepts_gzip,
+                })
+                .await;
+            }
+        }
+
+        Ok(not_found())
+    }
+}
+
+// the directory files are served from/written to, set via `--directory`
+fn files_directory() -> String {
+    let args: Vec<String> = args().collect();
+    match args.iter().position(|arg| arg == "--directory") {
+        None => "/".to_owned(),
+        Some(i) => args.get(i + 1).cloned().unwrap_or_else(|| "/".to_owned()),
+    }
+}
+
+// the max number of requests served on a single persistent connection before
+// it's forced closed, set via `--max-requests-per-connection`
+fn max_requests_per_connection() -> u32 {
+    let args: Vec<String> = args().collect();
+    match args.iter().position(|arg| arg == "--max-requests-per-connection") {
+        None => DEFAULT_MAX_REQUESTS_PER_CONNECTION,
+        Some(i) => args
+            .get(i + 1)
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_MAX_REQUESTS_PER_CONNECTION),
+    }
+}
+
+fn build_router() -> Router {
+    let mut router = Router::new();
+
+    router.add("GET", "/", |_input| async { Ok(respond(200, "OK")) });
+
+    router.add("GET", "/echo/:msg", |input| async move {
+        let msg = input.params.get("msg").cloned().unwrap_or_default();
+        Ok(respond_with_body(
+            "text/plain",
+            msg.into_bytes(),
+            input.accepts_gzip,
+        ))
+    });
+
+    router.add("GET", "/user-agent", |input| async move {
+        let user_agent = input.request.header("User-Agent").unwrap_or("").to_owned();
+        Ok(respond_with_body(
+            "text/plain",
+            user_agent.into_bytes(),
+            input.accepts_gzip,
+        ))
+    });
+
+    router.add("GET", "/files/:name", |input| async move {
+        let name = input.params.get("name").cloned().unwrap_or_default();
+        let file_path = files_directory() + name.as_str();
+
+        let Ok(mut file) = File::open(file_path).await else {
+            return Ok(not_found());
+        };
+        let metadata = file.metadata().await.context("couldn't stat file")?;
+        let modified = metadata.modified().context("couldn't read file mtime")?;
+        let etag = etag_for(metadata.len(), modified);
+
+        if request_has_current_cache(&input.request, &etag, modified) {
+            return Ok(respond_not_modified(etag, http_date(modified)));
+        }
+
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)
+            .await
+            .context("couldn't read from file")?;
+        let mut response =
+            respond_with_body("application/octet-stream", contents, input.accepts_gzip);
+        response.set_header("Last-Modified", http_date(modified));
+        response.set_header("ETag", etag);
+        Ok(response)
+    });
+
+    router.add("POST", "/files/:name", |input| async move {
+        let name = input.params.get("name").cloned().unwrap_or_default();
+        let file_path = files_directory() + name.as_str();
+
+        let mut file = File::create(file_path)
+            .await
+            .context("couldn't create new file")?;
+        file.write_all(&input.request.body)
+            .await
+            .context("couldn't write to file")?;
+        Ok(respond(201, "Created"))
+    });
+
+    router
 }
 
-// enumeration of possible endpoints on the server
-enum Endpoint {
-    Index,
-    Echo(String),
-    UserAgent,
-    File(String),
-    NotFound,
+// the result of `next_request`, which reads request bytes off `stream`,
+// pulling from `leftover` (bytes already read for a previous request on this
+// connection) first, and parses them into a `Request` once a full header
+// block and body have arrived.
+enum ReadOutcome {
+    Request(Request),
+    // peer closed the connection with no request pending
+    Closed,
+    // the declared `Content-Length` (or decoded chunked body) exceeds
+    // `MAX_REQUEST_SIZE`; the caller should reject the request without
+    // buffering the rest of the body
+    TooLarge,
+    // the header block exceeds `MAX_HTTP_MESSAGE_HEADER_SIZE`, whether or not
+    // its terminator has arrived yet
+    HeaderTooLarge,
+    // a header we need to trust (e.g. `Content-Length`) doesn't parse; the
+    // bytes the client still has queued up can't be safely skipped, so the
+    // caller should reject the request and close the connection
+    BadRequest,
 }
 
-// processes a single request from the TCP stream asynchronously
-async fn process_request(mut stream: TcpStream) -> Result<()> {
-    let mut buf = [0; MAX_REQUEST_SIZE];
-    let bytes_read = stream
-        .read(&mut buf)
+// reads from `stream` against a fixed `deadline`, surfacing both a timeout and
+// an underlying I/O failure as the same connection-ending error. callers
+// compute the deadline once per read phase, rather than re-arming it on every
+// individual read, so a client trickling one byte at a time can't hold the
+// phase open indefinitely
+async fn read_timeout(stream: &mut TcpStream, buf: &mut [u8], deadline: Instant) -> Result<usize> {
+    timeout_at(deadline, stream.read(buf))
         .await
-        .context("couldn't read from TCP stream")?;
-    let request_string = from_utf8(&buf[..bytes_read]).context("stream not in valid UTF-8")?;
-
-    let request = parse_str_to_request(request_string).context("couldn't parse HTTP request")?;
-
-    let endpoint = parse_target(request.request_line.target);
-    let response = match endpoint {
-        Endpoint::Index => respond(200, "OK"),
-        Endpoint::Echo(body) => respond_with_body("text/plain", body),
-        Endpoint::UserAgent => {
-            let user_agent_header = request
-                .headers
-                .iter()
-                .find(|header| header.key == "User-Agent");
-            let user_agent = match user_agent_header {
-                None => "".to_owned(),
-                Some(header) => header.value.to_owned(),
-            };
-            respond_with_body("text/plain", user_agent)
+        .context("read timed out")?
+        .context("couldn't read from TCP stream")
+}
+
+async fn next_request(stream: &mut TcpStream, leftover: &mut Vec<u8>) -> Result<ReadOutcome> {
+    let mut buf = std::mem::take(leftover);
+    let mut read_buf = [0; MAX_REQUEST_SIZE];
+
+    let header_deadline = Instant::now() + IDLE_TIMEOUT;
+    let header_end = loop {
+        if let Some(index) = find_header_terminator(&buf) {
+            if index > MAX_HTTP_MESSAGE_HEADER_SIZE {
+                return Ok(ReadOutcome::HeaderTooLarge);
+            }
+            break index;
         }
-        Endpoint::File(path) => {
-            let args: Vec<String> = args().collect();
-            let directory = match args.iter().position(|arg| arg == "--directory") {
-                None => "/",
-                Some(i) => match args.get(i + 1) {
-                    None => "/",
-                    Some(arg) => arg,
-                },
+        if buf.len() >= MAX_HTTP_MESSAGE_HEADER_SIZE {
+            return Ok(ReadOutcome::HeaderTooLarge);
+        }
+
+        let bytes_read = read_timeout(stream, &mut read_buf, header_deadline).await?;
+        if bytes_read == 0 {
+            return if buf.is_empty() {
+                Ok(ReadOutcome::Closed)
+            } else {
+                Err(anyhow!("connection closed mid-request"))
             };
-            let file_path = directory.to_owned() + path.as_str();
-
-            if request.request_line.method == "GET" {
-                let file_result = File::open(file_path).await;
-                if let Ok(mut file) = file_result {
-                    let mut contents = String::new();
-                    file.read_to_string(&mut contents)
-                        .await
-                        .context("couldn't read from file")?;
-                    respond_with_body("application/octet-stream", contents)
-                } else {
-                    not_found()
+        }
+        buf.extend_from_slice(&read_buf[..bytes_read]);
+    };
+
+    let header_str =
+        std::str::from_utf8(&buf[..header_end]).context("request headers not in valid UTF-8")?;
+    let (request_line, headers) = parse_header_block(header_str)?;
+    let body_start = header_end + 4;
+
+    let (body, body_end) = if transfer_encoding_is_chunked(&headers) {
+        match read_chunked_body(stream, &mut buf, body_start).await? {
+            Some(result) => result,
+            None => return Ok(ReadOutcome::TooLarge),
+        }
+    } else {
+        let content_length = match content_length_of(&headers) {
+            Ok(length) => length.unwrap_or(0),
+            Err(()) => return Ok(ReadOutcome::BadRequest),
+        };
+        if content_length > MAX_REQUEST_SIZE {
+            return Ok(ReadOutcome::TooLarge);
+        }
+        let body_end = body_start + content_length;
+
+        let body_deadline = Instant::now() + READ_TIMEOUT;
+        while buf.len() < body_end {
+            let bytes_read = read_timeout(stream, &mut read_buf, body_deadline).await?;
+            if bytes_read == 0 {
+                return Err(anyhow!("connection closed before full body arrived"));
+            }
+            buf.extend_from_slice(&read_buf[..bytes_read]);
+        }
+
+        (buf[body_start..body_end].to_vec(), body_end)
+    };
+
+    *leftover = buf.split_off(body_end);
+    Ok(ReadOutcome::Request(Request {
+        request_line,
+        headers,
+        body,
+    }))
+}
+
+fn transfer_encoding_is_chunked(headers: &[Header]) -> bool {
+    headers
+        .iter()
+        .find(|header| header.key.eq_ignore_ascii_case("Transfer-Encoding"))
+        .is_some_and(|header| header.value.trim().eq_ignore_ascii_case("chunked"))
+}
+
+// decodes a `Transfer-Encoding: chunked` body starting at `buf[start..]`,
+// reading more bytes off `stream` as needed. Returns the decoded body and
+// the offset into `buf` just past the terminating `0\r\n` chunk (and any
+// trailer headers), or `None` if the decoded body exceeds `MAX_REQUEST_SIZE`.
+async fn read_chunked_body(
+    stream: &mut TcpStream,
+    buf: &mut Vec<u8>,
+    start: usize,
+) -> Result<Option<(Vec<u8>, usize)>> {
+    let mut read_buf = [0; MAX_REQUEST_SIZE];
+    let mut body = Vec::new();
+    let mut pos = start;
+    // one deadline for the whole decode, not re-armed per read, so a client
+    // trickling bytes can't hold the body open past `READ_TIMEOUT`
+    let deadline = Instant::now() + READ_TIMEOUT;
+
+    loop {
+        let line_end = loop {
+            if let Some(rel) = buf[pos..].windows(2).position(|window| window == b"\r\n") {
+                break pos + rel;
+            }
+            let bytes_read = read_timeout(stream, &mut read_buf, deadline).await?;
+            if bytes_read == 0 {
+                return Err(anyhow!("connection closed mid-chunk"));
+            }
+            buf.extend_from_slice(&read_buf[..bytes_read]);
+        };
+
+        let size_line = std::str::from_utf8(&buf[pos..line_end])
+            .context("chunk size line not valid UTF-8")?;
+        let chunk_size = usize::from_str_radix(size_line.trim(), 16)
+            .context("invalid chunk size")?;
+        pos = line_end + 2;
+
+        // bail out before buffering a single byte of an oversized chunk,
+        // rather than trusting the client's declared size
+        if chunk_size > MAX_REQUEST_SIZE || body.len() > MAX_REQUEST_SIZE - chunk_size {
+            return Ok(None);
+        }
+
+        if chunk_size == 0 {
+            // consume any trailer headers, ending at the lone CRLF that closes them
+            loop {
+                let Some(rel) = buf[pos..].windows(2).position(|window| window == b"\r\n") else {
+                    let bytes_read = read_timeout(stream, &mut read_buf, deadline).await?;
+                    if bytes_read == 0 {
+                        return Err(anyhow!("connection closed mid-trailer"));
+                    }
+                    buf.extend_from_slice(&read_buf[..bytes_read]);
+                    continue;
+                };
+                let trailer_line_end = pos + rel;
+                let was_blank_line = trailer_line_end == pos;
+                pos = trailer_line_end + 2;
+                if was_blank_line {
+                    break;
                 }
-            } else if request.request_line.method == "POST" {
-                let mut file = File::create(file_path)
-                    .await
-                    .context("couldn't create new file")?;
-                file.write_all(request.body.as_bytes())
-                    .await
-                    .context("couldn't write to file")?;
-                respond(201, "Created")
-            } else {
-                not_found()
             }
+            return Ok(Some((body, pos)));
+        }
+
+        let chunk_end = pos + chunk_size;
+        while buf.len() < chunk_end + 2 {
+            let bytes_read = read_timeout(stream, &mut read_buf, deadline).await?;
+            if bytes_read == 0 {
+                return Err(anyhow!("connection closed mid-chunk"));
+            }
+            buf.extend_from_slice(&read_buf[..bytes_read]);
         }
-        Endpoint::NotFound => not_found(),
+
+        body.extend_from_slice(&buf[pos..chunk_end]);
+        pos = chunk_end + 2; // skip the CRLF that follows each chunk's data
+    }
+}
+
+fn find_header_terminator(buf: &[u8]) -> Option<usize> {
+    buf.windows(4).position(|window| window == b"\r\n\r\n")
+}
+
+// whether the client's `Accept-Encoding` header lists `gzip` as a
+// comma-separated, possibly-whitespace-padded token
+fn accept_encoding_offers_gzip(header: Option<&str>) -> bool {
+    header
+        .map(|value| {
+            value
+                .split(',')
+                .any(|token| token.trim().eq_ignore_ascii_case("gzip"))
+        })
+        .unwrap_or(false)
+}
+
+fn gzip_compress(contents: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(contents)
+        .expect("writing to an in-memory gzip encoder shouldn't fail");
+    encoder
+        .finish()
+        .expect("finishing an in-memory gzip encoder shouldn't fail")
+}
+
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+// days since the epoch (1970-01-01) for a given proleptic-Gregorian date,
+// per Howard Hinnant's `days_from_civil` algorithm
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = (y - era * 400) as u64;
+    let shifted_month = if month > 2 { month - 3 } else { month + 9 } as u64;
+    let doy = (153 * shifted_month + 2) / 5 + day as u64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe as i64 - 719468
+}
+
+// the inverse of `days_from_civil`
+fn civil_from_days(z: i64) -> (i64, u32, u32) {
+    let z = z + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = (z - era * 146097) as u64;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let y = yoe as i64 + era * 400;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = if month <= 2 { y + 1 } else { y };
+    (year, month, day)
+}
+
+// formats a Unix timestamp as an RFC 1123 date, e.g. `Tue, 15 Nov 1994 12:45:26 GMT`
+fn http_date(time: SystemTime) -> String {
+    let secs = unix_secs(time);
+    let days = (secs / 86400) as i64;
+    let time_of_day = secs % 86400;
+    let (year, month, day) = civil_from_days(days);
+    let weekday = WEEKDAYS[((days + 4).rem_euclid(7)) as usize];
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        weekday,
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        time_of_day / 3600,
+        (time_of_day % 3600) / 60,
+        time_of_day % 60,
+    )
+}
+
+// parses the fixed RFC 1123 format `http_date` emits; returns `None` for
+// anything else rather than attempting to support every HTTP-date variant
+fn parse_http_date(value: &str) -> Option<u64> {
+    let tokens: Vec<&str> = value.split_whitespace().collect();
+    let [_weekday, day, month, year, time, "GMT"] = tokens[..] else {
+        return None;
     };
+    let day: u32 = day.parse().ok()?;
+    let month = MONTHS.iter().position(|candidate| *candidate == month)? as u32 + 1;
+    let year: i64 = year.parse().ok()?;
 
-    let response_str = parse_response_to_str(response);
-    stream
-        .write_all(response_str.as_bytes())
-        .await
-        .context("couldn't write to TCP stream")?;
-    Ok(())
+    let mut time_parts = time.split(':');
+    let hour: u64 = time_parts.next()?.parse().ok()?;
+    let minute: u64 = time_parts.next()?.parse().ok()?;
+    let second: u64 = time_parts.next()?.parse().ok()?;
+    if time_parts.next().is_some() {
+        return None;
+    }
+
+    let days = days_from_civil(year, month, day);
+    Some(days as u64 * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+// an entity tag derived from file size and modification time (in whole
+// seconds); it is sent without a `W/` prefix and compared exactly
+fn etag_for(len: u64, modified: SystemTime) -> String {
+    format!("\"{:x}-{:x}\"", len, unix_secs(modified))
+}
+
+// whether `request` carries a conditional-GET header showing its cached copy
+// of the file is still current. `If-None-Match` takes precedence over
+// `If-Modified-Since` when both are present.
+fn request_has_current_cache(request: &Request, etag: &str, modified: SystemTime) -> bool {
+    if let Some(if_none_match) = request.header("If-None-Match") {
+        return if_none_match
+            .split(',')
+            .any(|candidate| candidate.trim() == etag);
+    }
+
+    if let Some(if_modified_since) = request.header("If-Modified-Since") {
+        return parse_http_date(if_modified_since.trim())
+            .is_some_and(|client_secs| client_secs >= unix_secs(modified));
+    }
+
+    false
+}
+
+// pulls `Content-Length` out of the already-parsed headers. `Ok(None)` means
+// the header is absent; `Err(())` means it's present but doesn't parse as a
+// `usize`, which callers must reject rather than treat as "no body"
+fn content_length_of(headers: &[Header]) -> Result<Option<usize>, ()> {
+    match headers
+        .iter()
+        .find(|header| header.key.eq_ignore_ascii_case("Content-Length"))
+    {
+        Some(header) => header.value.trim().parse().map(Some).map_err(|_| ()),
+        None => Ok(None),
+    }
+}
+
+// processes requests from a single TCP stream until the connection closes
+async fn process_request(
+    mut stream: TcpStream,
+    router: Arc<Router>,
+    max_requests_per_connection: u32,
+) -> Result<()> {
+    let mut leftover = Vec::new();
+    let mut requests_served: u32 = 0;
+
+    loop {
+        let request = match next_request(&mut stream, &mut leftover).await? {
+            ReadOutcome::Request(request) => request,
+            ReadOutcome::Closed => return Ok(()),
+            ReadOutcome::TooLarge => {
+                let response_bytes = parse_response_to_bytes(payload_too_large());
+                stream
+                    .write_all(&response_bytes)
+                    .await
+                    .context("couldn't write to TCP stream")?;
+                return Ok(());
+            }
+            ReadOutcome::HeaderTooLarge => {
+                let response_bytes = parse_response_to_bytes(header_fields_too_large());
+                stream
+                    .write_all(&response_bytes)
+                    .await
+                    .context("couldn't write to TCP stream")?;
+                return Ok(());
+            }
+            ReadOutcome::BadRequest => {
+                let response_bytes = parse_response_to_bytes(bad_request());
+                stream
+                    .write_all(&response_bytes)
+                    .await
+                    .context("couldn't write to TCP stream")?;
+                return Ok(());
+            }
+        };
+
+        requests_served += 1;
+        let keep_alive =
+            request.wants_keep_alive() && requests_served < max_requests_per_connection;
+        let accepts_gzip = accept_encoding_offers_gzip(request.header("Accept-Encoding"));
+
+        let mut response = router.dispatch(request, accepts_gzip).await?;
+
+        response.set_header(
+            "Connection",
+            (if keep_alive { "keep-alive" } else { "close" }).to_owned(),
+        );
+
+        let response_bytes = parse_response_to_bytes(response);
+        stream
+            .write_all(&response_bytes)
+            .await
+            .context("couldn't write to TCP stream")?;
+
+        if !keep_alive {
+            return Ok(());
+        }
+    }
 }
 
 #[tokio::main]
@@ -129,51 +699,29 @@ async fn main() -> Result<()> {
     let listener = TcpListener::bind("127.0.0.1:4221")
         .await
         .context("couldn't bind to 127.0.0.1:4221")?;
+    let router = Arc::new(build_router());
+    let max_requests_per_connection = max_requests_per_connection();
 
     loop {
         let (socket, _) = listener
             .accept()
             .await
             .context("couldn't accept new TCP socket")?;
+        let router = Arc::clone(&router);
 
-        spawn(async move { process_request(socket).await });
-    }
-}
-
-fn parse_target(target: String) -> Endpoint {
-    let mut components = target.split('/');
-    match components.next() {
-        None => return Endpoint::NotFound,
-        Some(string) => {
-            if !string.is_empty() {
-                return Endpoint::NotFound;
+        spawn(async move {
+            if let Err(err) = process_request(socket, router, max_requests_per_connection).await {
+                eprintln!("connection closed with error: {err:#}");
             }
-        }
-    }
-
-    let route = match components.next() {
-        None => return Endpoint::NotFound,
-        Some(string) => string,
-    };
-
-    match route {
-        "" => Endpoint::Index,
-        "echo" => match components.next() {
-            None => Endpoint::NotFound,
-            Some(string) => Endpoint::Echo(string.to_owned()),
-        },
-        "user-agent" => Endpoint::UserAgent,
-        "files" => match components.next() {
-            None => Endpoint::NotFound,
-            Some(string) => Endpoint::File(string.to_owned()),
-        },
-        _ => Endpoint::NotFound,
+        });
     }
 }
 
-fn parse_str_to_request(request: &str) -> Result<Request> {
-    // split into request line and headers at CRLF
-    let (request_line, headers_and_body) = request
+// parses the request line and headers out of the header block (everything
+// before the double CRLF); the body is handled separately since it isn't
+// guaranteed to be valid UTF-8
+fn parse_header_block(header_str: &str) -> Result<(RequestLine, Vec<Header>)> {
+    let (request_line, headers) = header_str
         .split_once("\r\n")
         .context("couldn't find first CRLF")?;
 
@@ -188,33 +736,27 @@ fn parse_str_to_request(request: &str) -> Result<Request> {
         version: request_line_components[2].to_owned(),
     };
 
-    // split off body
-    let (headers, body) = headers_and_body
-        .split_once("\r\n\r\n")
-        .context("couldn't find double CRLF")?;
-
     // parse headers
-    let parsed_headers: Vec<Header> = headers
-        .split("\r\n")
-        .map(|header| {
-            let (key, value) = header.split_once(": ")?;
-            Some(Header {
-                key: key.to_owned(),
-                value: value.to_owned(),
+    let parsed_headers: Vec<Header> = if headers.is_empty() {
+        Vec::new()
+    } else {
+        headers
+            .split("\r\n")
+            .map(|header| {
+                let (key, value) = header.split_once(": ")?;
+                Some(Header {
+                    key: key.to_owned(),
+                    value: value.to_owned(),
+                })
             })
-        })
-        .collect::<Option<Vec<Header>>>()
-        .context("failed to parse headers")?;
+            .collect::<Option<Vec<Header>>>()
+            .context("failed to parse headers")?
+    };
 
-    // return final product
-    Ok(Request {
-        request_line: parsed_request_line,
-        headers: parsed_headers,
-        body: body.to_owned(),
-    })
+    Ok((parsed_request_line, parsed_headers))
 }
 
-fn parse_response_to_str(response: Response) -> String {
+fn parse_response_to_bytes(response: Response) -> Vec<u8> {
     // deal with status line
     let status_line = response.status_line;
     let mut parsed_response = [
@@ -235,9 +777,26 @@ fn parse_response_to_str(response: Response) -> String {
     parsed_response += "\r\n\r\n";
 
     // deal with body
-    parsed_response += response.body.as_str();
+    let mut bytes = parsed_response.into_bytes();
+    if response.chunked {
+        bytes.extend_from_slice(&chunk_encode(&response.body));
+    } else {
+        bytes.extend_from_slice(&response.body);
+    }
+    bytes
+}
 
-    parsed_response
+// frames `body` as one or more `<hex-len>\r\n<bytes>\r\n` chunks ending with
+// the terminating `0\r\n\r\n` chunk
+fn chunk_encode(body: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    for chunk in body.chunks(CHUNK_SIZE) {
+        encoded.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+        encoded.extend_from_slice(chunk);
+        encoded.extend_from_slice(b"\r\n");
+    }
+    encoded.extend_from_slice(b"0\r\n\r\n");
+    encoded
 }
 
 // for responses with no headers or body
@@ -249,41 +808,235 @@ fn respond(status_code: i32, status_text: &str) -> Response {
             status_text: status_text.to_owned(),
         },
         headers: Vec::new(),
-        body: String::new(),
+        body: Vec::new(),
+        chunked: false,
     }
 }
 
-// for simple 200 responses with a body
-fn respond_with_body(content_type: &str, contents: String) -> Response {
+// for simple 200 responses with a body; compresses the body with gzip and
+// sets `Content-Encoding` when the client offered it, falling back to the
+// identity body unchanged otherwise. Large identity bodies are streamed with
+// `Transfer-Encoding: chunked` instead of a precomputed `Content-Length`.
+fn respond_with_body(content_type: &str, contents: Vec<u8>, accepts_gzip: bool) -> Response {
+    let mut headers = vec![Header {
+        key: "Content-Type".to_owned(),
+        value: content_type.to_owned(),
+    }];
+
+    let body = if accepts_gzip {
+        headers.push(Header {
+            key: "Content-Encoding".to_owned(),
+            value: "gzip".to_owned(),
+        });
+        gzip_compress(&contents)
+    } else {
+        contents
+    };
+
+    let chunked = !accepts_gzip && body.len() > CHUNKED_RESPONSE_THRESHOLD;
+    if chunked {
+        headers.push(Header {
+            key: "Transfer-Encoding".to_owned(),
+            value: "chunked".to_owned(),
+        });
+    } else {
+        headers.push(Header {
+            key: "Content-Length".to_owned(),
+            value: body.len().to_string(),
+        });
+    }
+
     Response {
         status_line: StatusLine {
             version: "HTTP/1.1".to_owned(),
             status_code: 200,
             status_text: "OK".to_owned(),
         },
+        headers,
+        body,
+        chunked,
+    }
+}
+
+// for 404 responses
+fn not_found() -> Response {
+    Response {
+        status_line: StatusLine {
+            version: "HTTP/1.1".to_owned(),
+            status_code: 404,
+            status_text: "Not Found".to_owned(),
+        },
+        headers: Vec::new(),
+        body: Vec::new(),
+        chunked: false,
+    }
+}
+
+// for conditional GETs whose `ETag`/`Last-Modified` show the client's cached
+// copy of the file is still current
+fn respond_not_modified(etag: String, last_modified: String) -> Response {
+    Response {
+        status_line: StatusLine {
+            version: "HTTP/1.1".to_owned(),
+            status_code: 304,
+            status_text: "Not Modified".to_owned(),
+        },
         headers: vec![
             Header {
-                key: "Content-Type".to_owned(),
-                value: content_type.to_owned(),
+                key: "ETag".to_owned(),
+                value: etag,
             },
             Header {
-                key: "Content-Length".to_owned(),
-                value: contents.len().to_string(),
+                key: "Last-Modified".to_owned(),
+                value: last_modified,
             },
         ],
-        body: contents,
+        body: Vec::new(),
+        chunked: false,
     }
 }
 
-// for 404 responses
-fn not_found() -> Response {
+// for requests with a `Content-Length` that isn't a valid `usize`. the
+// unread bytes the client still has queued can't be safely skipped, so the
+// connection is closed right after this is sent
+fn bad_request() -> Response {
     Response {
         status_line: StatusLine {
             version: "HTTP/1.1".to_owned(),
-            status_code: 404,
-            status_text: "Not Found".to_owned(),
+            status_code: 400,
+            status_text: "Bad Request".to_owned(),
         },
-        headers: Vec::new(),
-        body: String::new(),
+        headers: vec![Header {
+            key: "Connection".to_owned(),
+            value: "close".to_owned(),
+        }],
+        body: Vec::new(),
+        chunked: false,
+    }
+}
+
+// for request bodies whose declared `Content-Length` exceeds `MAX_REQUEST_SIZE`.
+// the connection is closed right after this is sent, so say so
+fn payload_too_large() -> Response {
+    Response {
+        status_line: StatusLine {
+            version: "HTTP/1.1".to_owned(),
+            status_code: 413,
+            status_text: "Payload Too Large".to_owned(),
+        },
+        headers: vec![Header {
+            key: "Connection".to_owned(),
+            value: "close".to_owned(),
+        }],
+        body: Vec::new(),
+        chunked: false,
+    }
+}
+
+// for request header blocks exceeding `MAX_HTTP_MESSAGE_HEADER_SIZE`. the
+// connection is closed right after this is sent, so say so
+fn header_fields_too_large() -> Response {
+    Response {
+        status_line: StatusLine {
+            version: "HTTP/1.1".to_owned(),
+            status_code: 431,
+            status_text: "Request Header Fields Too Large".to_owned(),
+        },
+        headers: vec![Header {
+            key: "Connection".to_owned(),
+            value: "close".to_owned(),
+        }],
+        body: Vec::new(),
+        chunked: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_encode_single_chunk() {
+        assert_eq!(chunk_encode(b"hello"), b"5\r\nhello\r\n0\r\n\r\n");
+    }
+
+    #[test]
+    fn chunk_encode_empty_body() {
+        assert_eq!(chunk_encode(b""), b"0\r\n\r\n");
+    }
+
+    #[test]
+    fn chunk_encode_splits_at_chunk_size() {
+        let body = vec![b'a'; CHUNK_SIZE + 10];
+        let encoded = chunk_encode(&body);
+        let first_chunk_header = format!("{:x}\r\n", CHUNK_SIZE);
+        assert!(encoded.starts_with(first_chunk_header.as_bytes()));
+    }
+
+    #[tokio::test]
+    async fn read_chunked_body_decodes_multiple_chunks() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+
+        client
+            .write_all(b"5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut buf = Vec::new();
+        let (body, body_end) = read_chunked_body(&mut server, &mut buf, 0)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(body, b"hello world");
+        assert_eq!(body_end, buf.len());
+    }
+
+    #[tokio::test]
+    async fn read_chunked_body_rejects_oversized_chunk() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+
+        client
+            .write_all(format!("{:x}\r\n", MAX_REQUEST_SIZE + 1).as_bytes())
+            .await
+            .unwrap();
+
+        let mut buf = Vec::new();
+        let result = read_chunked_body(&mut server, &mut buf, 0).await.unwrap();
+        assert!(result.is_none());
+    }
+
+    #[test]
+    fn civil_from_days_round_trips_days_from_civil() {
+        for days in [-719468, -1, 0, 1, 365, 10957, 19723] {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+
+    #[test]
+    fn http_date_matches_rfc_1123_example() {
+        // 1994-11-15T12:45:26Z is the example date from the RFC 1123 grammar
+        let time = UNIX_EPOCH + Duration::from_secs(784_903_526);
+        assert_eq!(http_date(time), "Tue, 15 Nov 1994 12:45:26 GMT");
+    }
+
+    #[test]
+    fn parse_http_date_round_trips_http_date() {
+        let time = UNIX_EPOCH + Duration::from_secs(784_903_526);
+        assert_eq!(parse_http_date(&http_date(time)), Some(unix_secs(time)));
+    }
+
+    #[test]
+    fn parse_http_date_rejects_garbage() {
+        assert_eq!(parse_http_date("not a date"), None);
     }
 }